@@ -1,8 +1,123 @@
 use crate::{ParseError, Span};
-use std::{collections::HashMap, sync::Arc};
+use memmap2::Mmap;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+/// Stable identifier for an interned source file. A given path always maps to the
+/// same `FileId` for the lifetime of the `ParserState`, even if its contents change.
+pub type FileId = usize;
+
+/// Files at or above this size are mmapped rather than read into a `Vec<u8>`
+/// when a lazily-registered file is first materialized.
+const LAZY_MMAP_THRESHOLD: u64 = 64 * 1024;
+
+/// The bytes backing a lazily-loaded file, read on first access.
+enum LazyContents {
+    Mapped(Mmap),
+    Owned(Vec<u8>),
+}
+
+impl std::ops::Deref for LazyContents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            LazyContents::Mapped(mmap) => mmap,
+            LazyContents::Owned(bytes) => bytes,
+        }
+    }
+}
+
+/// Reads the bytes for a lazily-registered file. Returns an error rather than
+/// falling back to empty contents, so a missing/unreadable file is never
+/// confused with a genuinely empty one.
+fn load_lazy_contents(path: &str) -> io::Result<LazyContents> {
+    let metadata = fs::metadata(path)?;
+
+    if metadata.len() >= LAZY_MMAP_THRESHOLD {
+        if let Ok(file) = fs::File::open(path) {
+            if let Ok(mmap) = unsafe { Mmap::map(&file) } {
+                return Ok(LazyContents::Mapped(mmap));
+            }
+        }
+    }
+
+    Ok(LazyContents::Owned(fs::read(path)?))
+}
+
+/// Where a file's bytes actually live: already in memory, deferred until first
+/// read (see `ParserState::add_file_path`), or as an offset into a
+/// memory-mapped on-disk cache (see `ParserState::new_from_cache`).
+enum FileStorage {
+    Owned(Vec<u8>),
+    Lazy {
+        path: String,
+        contents: OnceLock<LazyContents>,
+    },
+    Cached { offset: u32, len: u32 },
+}
 
 pub struct ParserState {
-    files: Vec<(String, Vec<u8>)>,
+    files: Vec<(String, FileStorage)>,
+    path_to_file_id: HashMap<String, FileId>,
+    changed_files: HashSet<FileId>,
+    /// mtime (seconds since epoch) recorded for each file in the cache this
+    /// state was loaded from by `new_from_cache`, used by `stale_files` to
+    /// detect on-disk edits. `write_cache` does not update it; files not
+    /// loaded from a cache have 0 here.
+    file_mtimes: Vec<u64>,
+    vars: HashMap<VarId, Type>,
+    symbol_index: SymbolIndex,
+    /// Backing mapping for any `FileStorage::Cached` entries above. Kept alive
+    /// for as long as the `ParserState` is, so cached slices stay valid.
+    mmap: Option<Mmap>,
+}
+
+/// Per-`VarId` declaration and use-site spans, so an LSP-style backend can
+/// answer "go to definition" and "find all references" without re-walking
+/// the AST. `span_to_var` is the reverse lookup for "what symbol is at this
+/// span".
+#[derive(Default)]
+struct SymbolIndex {
+    definitions: HashMap<VarId, Span>,
+    references: HashMap<VarId, Vec<Span>>,
+    span_to_var: HashMap<(usize, usize, usize), VarId>,
+}
+
+fn span_key(span: Span) -> (usize, usize, usize) {
+    (span.file_id, span.start, span.end)
+}
+
+impl SymbolIndex {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn record_definition(&mut self, var_id: VarId, span: Span) {
+        self.span_to_var.insert(span_key(span), var_id);
+        self.definitions.insert(var_id, span);
+    }
+
+    fn record_reference(&mut self, var_id: VarId, span: Span) {
+        self.span_to_var.insert(span_key(span), var_id);
+        self.references.entry(var_id).or_default().push(span);
+    }
+
+    fn merge(&mut self, other: SymbolIndex) {
+        for (var_id, span) in other.definitions {
+            self.record_definition(var_id, span);
+        }
+        for (var_id, spans) in other.references {
+            for span in spans {
+                self.record_reference(var_id, span);
+            }
+        }
+    }
 }
 
 pub enum VarLocation {
@@ -18,21 +133,30 @@ pub enum Type {
 
 pub type VarId = usize;
 
+/// One layer of the scope stack. `vars` holds names this frame adds or
+/// re-adds; `removed` holds names this frame explicitly masks (an
+/// `%unset`-style removal), so it can hide a binding from an outer frame
+/// without needing to know what that outer frame actually has.
 struct ScopeFrame {
     vars: HashMap<Vec<u8>, VarId>,
+    removed: HashSet<Vec<u8>>,
 }
 
 impl ScopeFrame {
     pub fn new() -> Self {
         Self {
             vars: HashMap::new(),
+            removed: HashSet::new(),
         }
     }
 }
 
 pub struct ParserWorkingSet {
     files: Vec<(String, Vec<u8>)>,
+    path_to_file_id: HashMap<String, FileId>,
+    updated_files: HashMap<FileId, Vec<u8>>,
     vars: HashMap<VarId, Type>,
+    symbol_index: SymbolIndex,
     permanent_state: Option<Arc<ParserState>>,
     scope: Vec<ScopeFrame>,
 }
@@ -43,18 +167,254 @@ impl Default for ParserState {
     }
 }
 
+/// Lexically normalize a path for interning purposes, without touching the
+/// filesystem (the path may not exist on disk yet, e.g. an unsaved buffer).
+///
+/// A leading `..` that has nothing to pop (relative path, or already at the
+/// start of one) is preserved rather than discarded, so e.g. `../foo.nu` and
+/// `foo.nu` intern to different `FileId`s instead of colliding. An absolute
+/// path can't climb above `/`, so a `..` there is simply dropped.
+fn canonicalize_path(path: &str) -> String {
+    let is_absolute = path.starts_with('/');
+    let mut parts: Vec<&str> = Vec::new();
+
+    for part in path.split('/') {
+        match part {
+            "" | "." => continue,
+            ".." => {
+                if !is_absolute && (parts.is_empty() || parts.last() == Some(&"..")) {
+                    parts.push("..");
+                } else {
+                    parts.pop();
+                }
+            }
+            _ => parts.push(part),
+        }
+    }
+
+    let mut result = if is_absolute {
+        String::from("/")
+    } else {
+        String::new()
+    };
+    result.push_str(&parts.join("/"));
+    result
+}
+
+/// On-disk cache format version. Bump whenever the docket or data blob layout
+/// changes, so an old cache is rejected instead of misread.
+const CACHE_FORMAT_VERSION: u32 = 1;
+
+/// Small, fixed-size header describing the on-disk cache, modeled on
+/// Mercurial's dirstate-v2 docket: a short file that just points at (and
+/// authenticates) the much larger data blob, so loading it is a handful of
+/// bytes plus an `mmap` call rather than a full parse.
+#[derive(Debug, Clone, Copy)]
+struct Docket {
+    format_version: u32,
+    data_uuid: [u8; 16],
+    num_files: u32,
+    content_hash: u64,
+}
+
+const DOCKET_SIZE: usize = 4 + 16 + 4 + 8;
+
+impl Docket {
+    fn to_bytes(self) -> [u8; DOCKET_SIZE] {
+        let mut buf = [0u8; DOCKET_SIZE];
+        buf[0..4].copy_from_slice(&self.format_version.to_le_bytes());
+        buf[4..20].copy_from_slice(&self.data_uuid);
+        buf[20..24].copy_from_slice(&self.num_files.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.content_hash.to_le_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < DOCKET_SIZE {
+            return None;
+        }
+
+        Some(Self {
+            format_version: u32::from_le_bytes(buf[0..4].try_into().ok()?),
+            data_uuid: buf[4..20].try_into().ok()?,
+            num_files: u32::from_le_bytes(buf[20..24].try_into().ok()?),
+            content_hash: u64::from_le_bytes(buf[24..32].try_into().ok()?),
+        })
+    }
+}
+
+/// Fixed-width descriptor for one file within the cache's data blob: offsets
+/// and lengths into the trailing string/byte arena, plus the mtime recorded
+/// at write time. Read directly out of the `mmap` with no per-entry
+/// allocation, the way `BytesCast`-style formats do.
+struct FileRecord {
+    name_offset: u32,
+    name_len: u32,
+    data_offset: u32,
+    data_len: u32,
+    mtime: u64,
+}
+
+const FILE_RECORD_SIZE: usize = 4 + 4 + 4 + 4 + 8;
+const VAR_RECORD_SIZE: usize = 8 + 1;
+
+impl FileRecord {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.name_offset.to_le_bytes());
+        out.extend_from_slice(&self.name_len.to_le_bytes());
+        out.extend_from_slice(&self.data_offset.to_le_bytes());
+        out.extend_from_slice(&self.data_len.to_le_bytes());
+        out.extend_from_slice(&self.mtime.to_le_bytes());
+    }
+
+    fn read(buf: &[u8]) -> Self {
+        Self {
+            name_offset: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            name_len: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            data_offset: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            data_len: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+            mtime: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+        }
+    }
+}
+
+/// Fixed-width descriptor for one symbol-index entry (a declaration or a
+/// single reference span): the owning `VarId` plus the `Span`'s three
+/// `usize` fields, narrowed to `u32` for the on-disk format.
+struct SymbolRecord {
+    var_id: u64,
+    file_id: u32,
+    start: u32,
+    end: u32,
+}
+
+const SYMBOL_RECORD_SIZE: usize = 8 + 4 + 4 + 4;
+
+impl SymbolRecord {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.var_id.to_le_bytes());
+        out.extend_from_slice(&self.file_id.to_le_bytes());
+        out.extend_from_slice(&self.start.to_le_bytes());
+        out.extend_from_slice(&self.end.to_le_bytes());
+    }
+
+    fn read(buf: &[u8]) -> Self {
+        Self {
+            var_id: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            file_id: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            start: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+            end: u32::from_le_bytes(buf[16..20].try_into().unwrap()),
+        }
+    }
+
+    fn span(&self) -> Span {
+        Span {
+            file_id: self.file_id as usize,
+            start: self.start as usize,
+            end: self.end as usize,
+        }
+    }
+}
+
+fn type_to_tag(ty: Type) -> u8 {
+    match ty {
+        Type::Int => 0,
+        Type::Unknown => 1,
+    }
+}
+
+fn type_from_tag(tag: u8) -> Type {
+    match tag {
+        0 => Type::Int,
+        _ => Type::Unknown,
+    }
+}
+
+/// FNV-1a, used as the cache's content hash: fast, dependency-free, and
+/// sufficient to catch a truncated or corrupted data file.
+fn fnv1a64(bytes: &[u8]) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = FNV_OFFSET;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Generates a data-file identifier that's unique per process, not
+/// cryptographically random: a timestamp plus a process-local counter,
+/// which is all that's needed to give each `write_cache` call its own
+/// data-file name.
+fn generate_uuid() -> [u8; 16] {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    let mut uuid = [0u8; 16];
+    uuid[0..8].copy_from_slice(&nanos.to_le_bytes());
+    uuid[8..16].copy_from_slice(&counter.to_le_bytes());
+    uuid
+}
+
+fn current_mtime(path: &str) -> u64 {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn data_file_path(docket_path: &Path, uuid: &[u8; 16]) -> PathBuf {
+    let mut hex = String::with_capacity(32);
+    for byte in uuid {
+        hex.push_str(&format!("{byte:02x}"));
+    }
+    docket_path.with_extension(format!("{hex}.dat"))
+}
+
 impl ParserState {
     pub fn new() -> Self {
-        Self { files: vec![] }
+        Self {
+            files: vec![],
+            path_to_file_id: HashMap::new(),
+            changed_files: HashSet::new(),
+            file_mtimes: vec![],
+            vars: HashMap::new(),
+            symbol_index: SymbolIndex::new(),
+            mmap: None,
+        }
     }
 
     pub fn merge_working_set(this: &mut Arc<ParserState>, mut working_set: ParserWorkingSet) {
         // Remove the working set's reference to the permanent state so we can safely take a mutable reference
         working_set.permanent_state = None;
 
-        // Take the mutable reference and extend the permanent state from the working set
+        // Take the mutable reference and reconcile the permanent state with the working set
         if let Some(this) = std::sync::Arc::<ParserState>::get_mut(this) {
-            this.files.extend(working_set.files);
+            for (file_id, contents) in working_set.updated_files {
+                this.files[file_id].1 = FileStorage::Owned(contents);
+                this.changed_files.insert(file_id);
+            }
+
+            for (filename, contents) in working_set.files {
+                this.add_file(filename, contents);
+            }
+
+            for (var_id, ty) in working_set.vars {
+                this.vars.insert(var_id, ty);
+            }
+
+            this.symbol_index.merge(working_set.symbol_index);
         } else {
             panic!("Internal error: merging working set should always succeed");
         }
@@ -64,14 +424,371 @@ impl ParserState {
         self.files.len()
     }
 
-    pub(crate) fn add_file(&mut self, filename: String, contents: Vec<u8>) -> usize {
-        self.files.push((filename, contents));
+    /// Number of `VarId`s already committed to this permanent state. A new
+    /// `ParserWorkingSet` offsets its own var ids by this so ids stay
+    /// globally unique across merges instead of starting over at 0 each time.
+    pub(crate) fn num_vars(&self) -> usize {
+        self.vars.len()
+    }
+
+    pub(crate) fn file_id_for_path(&self, path: &str) -> Option<FileId> {
+        self.path_to_file_id.get(&canonicalize_path(path)).copied()
+    }
+
+    /// Interns `filename`, returning its stable `FileId`. If the path was already
+    /// known, its contents are overwritten in place and the existing id is reused.
+    pub(crate) fn add_file(&mut self, filename: String, contents: Vec<u8>) -> FileId {
+        let key = canonicalize_path(&filename);
+
+        if let Some(&id) = self.path_to_file_id.get(&key) {
+            self.files[id].1 = FileStorage::Owned(contents);
+            self.changed_files.insert(id);
+            return id;
+        }
+
+        self.files.push((filename, FileStorage::Owned(contents)));
+        let id = self.num_files() - 1;
+        self.path_to_file_id.insert(key, id);
+        self.file_mtimes.push(0);
+        id
+    }
+
+    /// Registers `filename` without reading it: its bytes are materialized (and
+    /// cached, mmapping files at or above `LAZY_MMAP_THRESHOLD`) the first time
+    /// `get_file_contents` is called for it. Dedupes and assigns `FileId`s
+    /// exactly like `add_file`.
+    pub fn add_file_path(&mut self, filename: String) -> FileId {
+        let key = canonicalize_path(&filename);
+        let storage = FileStorage::Lazy {
+            path: filename.clone(),
+            contents: OnceLock::new(),
+        };
+
+        if let Some(&id) = self.path_to_file_id.get(&key) {
+            self.files[id].1 = storage;
+            self.changed_files.insert(id);
+            return id;
+        }
+
+        self.files.push((filename, storage));
+        let id = self.num_files() - 1;
+        self.path_to_file_id.insert(key, id);
+        self.file_mtimes.push(0);
+        id
+    }
+
+    /// Overwrites the contents of an already-registered file, or registers it if
+    /// it isn't known yet. Unlike `add_file`, this is the public entry point a
+    /// driver (e.g. a REPL re-sourcing an edited file) is expected to call.
+    pub fn set_file_contents(&mut self, filename: String, contents: Vec<u8>) -> FileId {
+        self.add_file(filename, contents)
+    }
+
+    pub(crate) fn get_file_contents(&self, idx: FileId) -> &[u8] {
+        match &self.files[idx].1 {
+            FileStorage::Owned(bytes) => bytes,
+            FileStorage::Lazy { path, contents } => {
+                if let Some(loaded) = contents.get() {
+                    loaded
+                } else {
+                    // Only cache a successful read. A transient failure (file
+                    // not yet flushed, permissions race, …) stays uncached so
+                    // the next access can retry instead of being stuck with a
+                    // silently-empty file forever.
+                    match load_lazy_contents(path) {
+                        Ok(loaded) => contents.get_or_init(|| loaded),
+                        Err(_) => &[],
+                    }
+                }
+            }
+            FileStorage::Cached { offset, len } => {
+                let mmap = self
+                    .mmap
+                    .as_ref()
+                    .expect("file backed by cache offsets without a loaded mmap");
+                &mmap[*offset as usize..*offset as usize + *len as usize]
+            }
+        }
+    }
+
+    /// `FileId`s whose contents changed since the last call to `clear_changed_files`.
+    /// A driver can use this to know which files need to be re-parsed.
+    pub fn changed_files(&self) -> &HashSet<FileId> {
+        &self.changed_files
+    }
+
+    pub fn clear_changed_files(&mut self) {
+        self.changed_files.clear();
+    }
+
+    /// The `Span` where `var_id` was declared, if known.
+    pub fn definition_span(&self, var_id: VarId) -> Option<Span> {
+        self.symbol_index.definitions.get(&var_id).copied()
+    }
+
+    /// All use-site `Span`s recorded for `var_id`.
+    pub fn references(&self, var_id: VarId) -> Vec<Span> {
+        self.symbol_index
+            .references
+            .get(&var_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The `VarId` declared or referenced at exactly `span`, if any.
+    pub fn symbol_at(&self, span: Span) -> Option<VarId> {
+        self.symbol_index.span_to_var.get(&span_key(span)).copied()
+    }
+
+    /// Serializes this permanent state to an on-disk cache so the next process
+    /// can reload it zero-copy via `new_from_cache` instead of re-parsing.
+    ///
+    /// This writes two files next to `docket_path`: the docket itself (a small
+    /// fixed-size header) and a data blob named after a freshly generated UUID,
+    /// mirroring Mercurial's dirstate-v2 docket/data-file split so a reader
+    /// that opened the old data file before this call keeps working with it.
+    /// The docket itself is swapped in via a write-to-temp-then-rename, so a
+    /// crash mid-write leaves the previous docket intact instead of a
+    /// truncated one. Once the new docket is in place, the previous data file
+    /// it pointed at (if any) is removed so repeated calls don't leak `.dat`
+    /// files.
+    pub fn write_cache(&self, docket_path: &Path) -> io::Result<()> {
+        let def_records: Vec<SymbolRecord> = self
+            .symbol_index
+            .definitions
+            .iter()
+            .map(|(var_id, span)| SymbolRecord {
+                var_id: *var_id as u64,
+                file_id: span.file_id as u32,
+                start: span.start as u32,
+                end: span.end as u32,
+            })
+            .collect();
+
+        let ref_records: Vec<SymbolRecord> = self
+            .symbol_index
+            .references
+            .iter()
+            .flat_map(|(var_id, spans)| {
+                spans.iter().map(move |span| SymbolRecord {
+                    var_id: *var_id as u64,
+                    file_id: span.file_id as u32,
+                    start: span.start as u32,
+                    end: span.end as u32,
+                })
+            })
+            .collect();
+
+        const BLOB_HEADER_SIZE: usize = 4 + 4 + 4;
+        let arena_base = BLOB_HEADER_SIZE
+            + self.files.len() * FILE_RECORD_SIZE
+            + self.vars.len() * VAR_RECORD_SIZE
+            + def_records.len() * SYMBOL_RECORD_SIZE
+            + ref_records.len() * SYMBOL_RECORD_SIZE;
+
+        let mut arena = Vec::new();
+        let mut file_records = Vec::with_capacity(self.files.len());
+
+        for (id, (name, _)) in self.files.iter().enumerate() {
+            let name_offset = (arena_base + arena.len()) as u32;
+            arena.extend_from_slice(name.as_bytes());
+            let name_len = name.len() as u32;
+
+            let contents = self.get_file_contents(id);
+            let data_offset = (arena_base + arena.len()) as u32;
+            arena.extend_from_slice(contents);
+            let data_len = contents.len() as u32;
+
+            let mtime = current_mtime(name);
+
+            file_records.push(FileRecord {
+                name_offset,
+                name_len,
+                data_offset,
+                data_len,
+                mtime,
+            });
+        }
+
+        let mut blob = Vec::with_capacity(arena_base + arena.len());
+        blob.extend_from_slice(&(self.vars.len() as u32).to_le_bytes());
+        blob.extend_from_slice(&(def_records.len() as u32).to_le_bytes());
+        blob.extend_from_slice(&(ref_records.len() as u32).to_le_bytes());
+        for record in &file_records {
+            record.write(&mut blob);
+        }
+        for (var_id, ty) in &self.vars {
+            blob.extend_from_slice(&(*var_id as u64).to_le_bytes());
+            blob.push(type_to_tag(*ty));
+        }
+        for record in &def_records {
+            record.write(&mut blob);
+        }
+        for record in &ref_records {
+            record.write(&mut blob);
+        }
+        blob.extend_from_slice(&arena);
+        debug_assert_eq!(blob.len(), arena_base + arena.len());
+
+        // Read the docket we're about to replace, if any, so its data file
+        // can be cleaned up once the new one is safely in place.
+        let previous_data_path = fs::read(docket_path)
+            .ok()
+            .and_then(|bytes| Docket::from_bytes(&bytes))
+            .map(|docket| data_file_path(docket_path, &docket.data_uuid));
+
+        let data_uuid = generate_uuid();
+        let data_path = data_file_path(docket_path, &data_uuid);
+        fs::write(&data_path, &blob)?;
+
+        let docket = Docket {
+            format_version: CACHE_FORMAT_VERSION,
+            data_uuid,
+            num_files: self.files.len() as u32,
+            content_hash: fnv1a64(&blob),
+        };
+
+        let temp_docket_path = docket_path.with_extension("docket.tmp");
+        fs::write(&temp_docket_path, docket.to_bytes())?;
+        fs::rename(&temp_docket_path, docket_path)?;
+
+        if let Some(previous_data_path) = previous_data_path {
+            if previous_data_path != data_path {
+                let _ = fs::remove_file(previous_data_path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Loads a permanent state written by `write_cache`. The data file is
+    /// mapped into memory and file contents are served as borrows into the
+    /// mapping, so loading is a docket read plus an `mmap` call, not a parse.
+    ///
+    /// Returns an error (rather than a partially-built state) if the docket is
+    /// truncated, its version doesn't match, or the data blob's content hash
+    /// doesn't match what the docket recorded — a caller should treat any
+    /// error here as "cold parse instead", since a corrupt cache must never be
+    /// allowed to silently yield wrong results.
+    pub fn new_from_cache(docket_path: &Path) -> io::Result<ParserState> {
+        let docket_bytes = fs::read(docket_path)?;
+        let docket = Docket::from_bytes(&docket_bytes)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated docket"))?;
+
+        if docket.format_version != CACHE_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "parser state cache format version mismatch",
+            ));
+        }
+
+        let data_path = data_file_path(docket_path, &docket.data_uuid);
+        let file = fs::File::open(&data_path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        if fnv1a64(&mmap) != docket.content_hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "parser state cache content hash mismatch",
+            ));
+        }
+
+        const BLOB_HEADER_SIZE: usize = 4 + 4 + 4;
+        if mmap.len() < BLOB_HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated parser state cache data",
+            ));
+        }
+        let num_vars = u32::from_le_bytes(mmap[0..4].try_into().unwrap()) as usize;
+        let num_defs = u32::from_le_bytes(mmap[4..8].try_into().unwrap()) as usize;
+        let num_refs = u32::from_le_bytes(mmap[8..12].try_into().unwrap()) as usize;
+
+        let file_table_start = BLOB_HEADER_SIZE;
+        let file_table_end = file_table_start + docket.num_files as usize * FILE_RECORD_SIZE;
+        let var_table_end = file_table_end + num_vars * VAR_RECORD_SIZE;
+        let def_table_end = var_table_end + num_defs * SYMBOL_RECORD_SIZE;
+        let ref_table_end = def_table_end + num_refs * SYMBOL_RECORD_SIZE;
+
+        if mmap.len() < ref_table_end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated parser state cache data",
+            ));
+        }
+
+        let mut files = Vec::with_capacity(docket.num_files as usize);
+        let mut path_to_file_id = HashMap::with_capacity(docket.num_files as usize);
+        let mut file_mtimes = Vec::with_capacity(docket.num_files as usize);
 
-        self.num_files() - 1
+        for i in 0..docket.num_files as usize {
+            let start = file_table_start + i * FILE_RECORD_SIZE;
+            let record = FileRecord::read(&mmap[start..start + FILE_RECORD_SIZE]);
+
+            let name_start = record.name_offset as usize;
+            let name_end = name_start + record.name_len as usize;
+            let name = String::from_utf8_lossy(&mmap[name_start..name_end]).into_owned();
+
+            path_to_file_id.insert(canonicalize_path(&name), i);
+            file_mtimes.push(record.mtime);
+            files.push((
+                name,
+                FileStorage::Cached {
+                    offset: record.data_offset,
+                    len: record.data_len,
+                },
+            ));
+        }
+
+        let mut vars = HashMap::with_capacity(num_vars);
+        for i in 0..num_vars {
+            let start = file_table_end + i * VAR_RECORD_SIZE;
+            let record = &mmap[start..start + VAR_RECORD_SIZE];
+            let var_id = u64::from_le_bytes(record[0..8].try_into().unwrap()) as VarId;
+            vars.insert(var_id, type_from_tag(record[8]));
+        }
+
+        let mut symbol_index = SymbolIndex::new();
+        for i in 0..num_defs {
+            let start = var_table_end + i * SYMBOL_RECORD_SIZE;
+            let record = SymbolRecord::read(&mmap[start..start + SYMBOL_RECORD_SIZE]);
+            symbol_index.record_definition(record.var_id as VarId, record.span());
+        }
+        for i in 0..num_refs {
+            let start = def_table_end + i * SYMBOL_RECORD_SIZE;
+            let record = SymbolRecord::read(&mmap[start..start + SYMBOL_RECORD_SIZE]);
+            symbol_index.record_reference(record.var_id as VarId, record.span());
+        }
+
+        Ok(ParserState {
+            files,
+            path_to_file_id,
+            changed_files: HashSet::new(),
+            file_mtimes,
+            vars,
+            symbol_index,
+            mmap: Some(mmap),
+        })
     }
 
-    pub(crate) fn get_file_contents(&self, idx: usize) -> &[u8] {
-        &self.files[idx].1
+    /// `FileId`s whose on-disk mtime no longer matches what was recorded in
+    /// the cache this state was loaded from. A file with no recorded mtime
+    /// (one not loaded from a cache) counts as stale whenever it exists on
+    /// disk. A driver should treat a
+    /// non-empty result as "this cache is stale, re-parse these files" (or
+    /// fall back to a cold parse entirely) rather than trusting cached bytes
+    /// that no longer reflect the file on disk.
+    pub fn stale_files(&self) -> HashSet<FileId> {
+        let mut stale = HashSet::new();
+
+        for (id, (name, _)) in self.files.iter().enumerate() {
+            let recorded = self.file_mtimes.get(id).copied().unwrap_or(0);
+            if current_mtime(name) != recorded {
+                stale.insert(id);
+            }
+        }
+
+        stale
     }
 }
 
@@ -79,33 +796,70 @@ impl ParserWorkingSet {
     pub fn new(permanent_state: Option<Arc<ParserState>>) -> Self {
         Self {
             files: vec![],
+            path_to_file_id: HashMap::new(),
+            updated_files: HashMap::new(),
             vars: HashMap::new(),
+            symbol_index: SymbolIndex::new(),
             permanent_state,
             scope: vec![],
         }
     }
 
-    pub fn num_files(&self) -> usize {
-        let parent_len = if let Some(permanent_state) = &self.permanent_state {
-            permanent_state.num_files()
-        } else {
-            0
-        };
+    fn num_permanent_files(&self) -> usize {
+        self.permanent_state
+            .as_ref()
+            .map_or(0, |permanent_state| permanent_state.num_files())
+    }
 
-        self.files.len() + parent_len
+    fn num_permanent_vars(&self) -> usize {
+        self.permanent_state
+            .as_ref()
+            .map_or(0, |permanent_state| permanent_state.num_vars())
     }
 
-    pub fn add_file(&mut self, filename: String, contents: Vec<u8>) -> usize {
+    pub fn num_files(&self) -> usize {
+        self.files.len() + self.num_permanent_files()
+    }
+
+    /// Interns `filename`, returning its `FileId`. If the path already exists in
+    /// the permanent state or earlier in this working set, its contents are
+    /// overwritten and the existing id is reused instead of allocating a new one.
+    pub fn add_file(&mut self, filename: String, contents: Vec<u8>) -> FileId {
+        let key = canonicalize_path(&filename);
+
+        if let Some(permanent_state) = &self.permanent_state {
+            if let Some(id) = permanent_state.file_id_for_path(&key) {
+                self.updated_files.insert(id, contents);
+                return id;
+            }
+        }
+
+        if let Some(&id) = self.path_to_file_id.get(&key) {
+            self.files[id - self.num_permanent_files()].1 = contents;
+            return id;
+        }
+
         self.files.push((filename, contents));
+        let id = self.num_files() - 1;
+        self.path_to_file_id.insert(key, id);
+        id
+    }
 
-        self.num_files() - 1
+    /// Same as `add_file`, but named to match the overwrite-in-place behavior a
+    /// caller relies on when re-sourcing an already-open file.
+    pub fn set_file_contents(&mut self, filename: String, contents: Vec<u8>) -> FileId {
+        self.add_file(filename, contents)
     }
 
     pub fn get_span_contents(&self, span: Span) -> &[u8] {
         if let Some(permanent_state) = &self.permanent_state {
             let num_permanent_files = permanent_state.num_files();
             if span.file_id < num_permanent_files {
-                &permanent_state.get_file_contents(span.file_id)[span.start..span.end]
+                if let Some(contents) = self.updated_files.get(&span.file_id) {
+                    &contents[span.start..span.end]
+                } else {
+                    &permanent_state.get_file_contents(span.file_id)[span.start..span.end]
+                }
             } else {
                 &self.files[span.file_id - num_permanent_files].1[span.start..span.end]
             }
@@ -119,40 +873,115 @@ impl ParserWorkingSet {
     }
 
     pub fn exit_scope(&mut self) {
-        self.scope.push(ScopeFrame::new());
+        self.scope.pop();
     }
 
+    /// Resolves `name` by walking frames outermost-to-innermost, so an inner
+    /// frame's addition or removal always wins over an outer one: later
+    /// additions shadow, later removals mask, and a later addition after a
+    /// removal unmasks again (the layered-config semantics this models).
     pub fn find_variable(&self, name: &[u8]) -> Option<(VarId, VarLocation, Type)> {
-        for scope in self.scope.iter().rev().enumerate() {
-            if let Some(var_id) = scope.1.vars.get(name) {
-                if let Some(result) = self.vars.get(var_id) {
-                    if scope.0 == 0 {
-                        // Top level
-                        return Some((*var_id, VarLocation::CurrentScope, *result));
-                    } else {
-                        return Some((*var_id, VarLocation::OuterScope, *result));
-                    }
-                }
+        let mut found: Option<(usize, VarId)> = None;
+
+        for (idx, frame) in self.scope.iter().enumerate() {
+            if frame.removed.contains(name) {
+                found = None;
+            }
+            if let Some(&var_id) = frame.vars.get(name) {
+                found = Some((idx, var_id));
             }
         }
 
-        None
+        let (idx, var_id) = found?;
+        let ty = *self.vars.get(&var_id)?;
+        let location = if idx == self.scope.len() - 1 {
+            VarLocation::CurrentScope
+        } else {
+            VarLocation::OuterScope
+        };
+
+        Some((var_id, location, ty))
     }
 
-    pub fn add_variable(&mut self, name: Vec<u8>, ty: Type) -> VarId {
+    /// Allocates a fresh `VarId`, declares `name` in the current scope, and
+    /// records `declaration` in the symbol index. Ids are offset by the
+    /// permanent state's var count so they stay globally unique across
+    /// successive `merge_working_set` calls instead of colliding with ids a
+    /// previous working set already committed.
+    pub fn add_variable(&mut self, name: Vec<u8>, declaration: Span, ty: Type) -> VarId {
         let last = self
             .scope
             .last_mut()
             .expect("internal error: missing stack frame");
 
-        let next_id = self.vars.len();
+        let next_id = self.num_permanent_vars() + self.vars.len();
 
+        last.removed.remove(&name);
         last.vars.insert(name, next_id);
 
         self.vars.insert(next_id, ty);
+        self.symbol_index.record_definition(next_id, declaration);
 
         next_id
     }
+
+    /// Masks `name` in the current frame (an `%unset`-style removal), so
+    /// `find_variable` stops seeing whatever an outer frame bound it to,
+    /// without disturbing that outer frame's own binding.
+    pub fn unset_variable(&mut self, name: &[u8]) {
+        let last = self
+            .scope
+            .last_mut()
+            .expect("internal error: missing stack frame");
+
+        last.vars.remove(name);
+        last.removed.insert(name.to_vec());
+    }
+
+    /// Records that `var_id` was referenced at `span`, for `references` to
+    /// later answer "find all references".
+    pub fn record_reference(&mut self, var_id: VarId, span: Span) {
+        self.symbol_index.record_reference(var_id, span);
+    }
+
+    /// The `Span` where `var_id` was declared, if known, checking this
+    /// working set before falling back to the permanent state.
+    pub fn definition_span(&self, var_id: VarId) -> Option<Span> {
+        self.symbol_index.definitions.get(&var_id).copied().or_else(|| {
+            self.permanent_state
+                .as_ref()
+                .and_then(|permanent_state| permanent_state.definition_span(var_id))
+        })
+    }
+
+    /// All use-site `Span`s recorded for `var_id`, across both this working
+    /// set and the permanent state.
+    pub fn references(&self, var_id: VarId) -> Vec<Span> {
+        let mut refs = self
+            .permanent_state
+            .as_ref()
+            .map(|permanent_state| permanent_state.references(var_id))
+            .unwrap_or_default();
+
+        if let Some(local) = self.symbol_index.references.get(&var_id) {
+            refs.extend_from_slice(local);
+        }
+
+        refs
+    }
+
+    /// The `VarId` declared or referenced at exactly `span`, if any.
+    pub fn symbol_at(&self, span: Span) -> Option<VarId> {
+        self.symbol_index
+            .span_to_var
+            .get(&span_key(span))
+            .copied()
+            .or_else(|| {
+                self.permanent_state
+                    .as_ref()
+                    .and_then(|permanent_state| permanent_state.symbol_at(span))
+            })
+    }
 }
 
 #[cfg(test)]
@@ -167,6 +996,25 @@ mod parser_state_tests {
         assert_eq!(id, 0);
     }
 
+    #[test]
+    fn canonicalize_path_preserves_leading_dotdot() {
+        assert_eq!(canonicalize_path("../foo.nu"), "../foo.nu");
+        assert_eq!(canonicalize_path("../../foo.nu"), "../../foo.nu");
+        assert_eq!(canonicalize_path("bar/../foo.nu"), "foo.nu");
+        assert_eq!(canonicalize_path("/../foo.nu"), "/foo.nu");
+    }
+
+    #[test]
+    fn leading_dotdot_does_not_collide_with_sibling_file() {
+        let mut parser_state = ParserState::new();
+        let sibling_id = parser_state.add_file("foo.nu".into(), b"sibling".to_vec());
+        let parent_id = parser_state.add_file("../foo.nu".into(), b"parent".to_vec());
+
+        assert_ne!(sibling_id, parent_id);
+        assert_eq!(parser_state.get_file_contents(sibling_id), b"sibling");
+        assert_eq!(parser_state.get_file_contents(parent_id), b"parent");
+    }
+
     #[test]
     fn add_file_gives_id_including_parent() {
         let mut parser_state = ParserState::new();
@@ -193,5 +1041,336 @@ mod parser_state_tests {
         assert_eq!(parser_state.num_files(), 2);
         assert_eq!(&parser_state.files[0].0, "test.nu");
         assert_eq!(&parser_state.files[1].0, "child.nu");
+        assert_eq!(parent_id, 0);
+    }
+
+    #[test]
+    fn set_file_contents_reuses_id_and_overwrites() {
+        let mut parser_state = ParserState::new();
+        let id = parser_state.add_file("test.nu".into(), b"old".to_vec());
+
+        let new_id = parser_state.set_file_contents("test.nu".into(), b"new".to_vec());
+
+        assert_eq!(id, new_id);
+        assert_eq!(parser_state.get_file_contents(new_id), b"new");
+        assert!(parser_state.changed_files().contains(&new_id));
+    }
+
+    #[test]
+    fn working_set_overwrite_of_permanent_file_merges_in_place() {
+        let mut parser_state = ParserState::new();
+        let parent_id = parser_state.add_file("test.nu".into(), b"old".to_vec());
+        let mut parser_state = Arc::new(parser_state);
+
+        let mut working_set = ParserWorkingSet::new(Some(parser_state.clone()));
+        let id = working_set.set_file_contents("test.nu".into(), b"new".to_vec());
+
+        assert_eq!(id, parent_id);
+
+        ParserState::merge_working_set(&mut parser_state, working_set);
+
+        assert_eq!(parser_state.num_files(), 1);
+        assert_eq!(parser_state.get_file_contents(parent_id), b"new");
+        assert!(parser_state.changed_files().contains(&parent_id));
+    }
+
+    #[test]
+    fn cache_round_trips_files_and_vars() {
+        let mut parser_state = ParserState::new();
+        parser_state.add_file("test.nu".into(), b"let x = 1".to_vec());
+        parser_state.vars.insert(0, Type::Int);
+
+        let docket_path =
+            std::env::temp_dir().join(format!("nu_parser_state_cache_{}.docket", std::process::id()));
+
+        parser_state.write_cache(&docket_path).unwrap();
+        let loaded = ParserState::new_from_cache(&docket_path).unwrap();
+
+        assert_eq!(loaded.num_files(), 1);
+        assert_eq!(loaded.get_file_contents(0), b"let x = 1");
+        assert!(matches!(loaded.vars.get(&0), Some(Type::Int)));
+
+        let docket = Docket::from_bytes(&std::fs::read(&docket_path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&docket_path);
+        let _ = std::fs::remove_file(data_file_path(&docket_path, &docket.data_uuid));
+    }
+
+    #[test]
+    fn write_cache_removes_previous_data_file() {
+        let mut parser_state = ParserState::new();
+        parser_state.add_file("test.nu".into(), b"let x = 1".to_vec());
+
+        let docket_path = std::env::temp_dir().join(format!(
+            "nu_parser_state_gc_cache_{}.docket",
+            std::process::id()
+        ));
+
+        parser_state.write_cache(&docket_path).unwrap();
+        let first_docket = Docket::from_bytes(&std::fs::read(&docket_path).unwrap()).unwrap();
+        let first_data_path = data_file_path(&docket_path, &first_docket.data_uuid);
+        assert!(first_data_path.exists());
+
+        parser_state.write_cache(&docket_path).unwrap();
+        let second_docket = Docket::from_bytes(&std::fs::read(&docket_path).unwrap()).unwrap();
+        let second_data_path = data_file_path(&docket_path, &second_docket.data_uuid);
+
+        assert!(!first_data_path.exists());
+        assert!(second_data_path.exists());
+
+        let _ = std::fs::remove_file(&docket_path);
+        let _ = std::fs::remove_file(&second_data_path);
+    }
+
+    #[test]
+    fn cache_rejects_truncated_docket() {
+        let docket_path = std::env::temp_dir().join(format!(
+            "nu_parser_state_bad_cache_{}.docket",
+            std::process::id()
+        ));
+        std::fs::write(&docket_path, b"short").unwrap();
+
+        assert!(ParserState::new_from_cache(&docket_path).is_err());
+
+        let _ = std::fs::remove_file(&docket_path);
+    }
+
+    #[test]
+    fn add_file_path_defers_reading_until_accessed() {
+        let path = std::env::temp_dir().join(format!("nu_lazy_file_{}.nu", std::process::id()));
+        std::fs::write(&path, b"let x = 1").unwrap();
+
+        let mut parser_state = ParserState::new();
+        let id = parser_state.add_file_path(path.to_string_lossy().into_owned());
+
+        assert_eq!(parser_state.get_file_contents(id), b"let x = 1");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn add_file_path_retries_after_transient_read_failure() {
+        let path = std::env::temp_dir().join(format!(
+            "nu_lazy_missing_file_{}.nu",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let mut parser_state = ParserState::new();
+        let id = parser_state.add_file_path(path.to_string_lossy().into_owned());
+
+        // The file doesn't exist yet: accessing it must not silently cache
+        // an empty result.
+        assert_eq!(parser_state.get_file_contents(id), b"" as &[u8]);
+
+        // Once it shows up on disk, the next access should see its contents
+        // rather than the earlier failure being stuck forever.
+        std::fs::write(&path, b"let x = 1").unwrap();
+        assert_eq!(parser_state.get_file_contents(id), b"let x = 1");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn symbol_index_tracks_definitions_and_references() {
+        let mut working_set = ParserWorkingSet::new(Some(Arc::new(ParserState::new())));
+        working_set.enter_scope();
+
+        let decl_span = Span {
+            file_id: 0,
+            start: 0,
+            end: 1,
+        };
+        let ref_span = Span {
+            file_id: 0,
+            start: 10,
+            end: 11,
+        };
+
+        let var_id = working_set.add_variable(b"x".to_vec(), decl_span, Type::Int);
+        working_set.record_reference(var_id, ref_span);
+
+        assert_eq!(
+            (
+                working_set.definition_span(var_id).unwrap().start,
+                working_set.definition_span(var_id).unwrap().end
+            ),
+            (0, 1)
+        );
+        assert_eq!(working_set.references(var_id).len(), 1);
+        assert_eq!(working_set.symbol_at(decl_span), Some(var_id));
+        assert_eq!(working_set.symbol_at(ref_span), Some(var_id));
+    }
+
+    #[test]
+    fn symbol_index_merges_and_survives_cache_round_trip() {
+        let mut parser_state = Arc::new(ParserState::new());
+
+        let mut working_set = ParserWorkingSet::new(Some(parser_state.clone()));
+        working_set.enter_scope();
+        let decl_span = Span {
+            file_id: 0,
+            start: 0,
+            end: 1,
+        };
+        let var_id = working_set.add_variable(b"x".to_vec(), decl_span, Type::Int);
+        let ref_span = Span {
+            file_id: 0,
+            start: 10,
+            end: 11,
+        };
+        working_set.record_reference(var_id, ref_span);
+
+        ParserState::merge_working_set(&mut parser_state, working_set);
+
+        let definition = parser_state.definition_span(var_id).unwrap();
+        assert_eq!((definition.start, definition.end), (0, 1));
+        assert_eq!(parser_state.references(var_id).len(), 1);
+        assert_eq!(parser_state.symbol_at(ref_span), Some(var_id));
+
+        let docket_path = std::env::temp_dir().join(format!(
+            "nu_parser_state_symbol_cache_{}.docket",
+            std::process::id()
+        ));
+        parser_state.write_cache(&docket_path).unwrap();
+        let loaded = ParserState::new_from_cache(&docket_path).unwrap();
+
+        let definition = loaded.definition_span(var_id).unwrap();
+        assert_eq!((definition.start, definition.end), (0, 1));
+        assert_eq!(loaded.references(var_id).len(), 1);
+        assert_eq!(loaded.symbol_at(decl_span), Some(var_id));
+
+        let docket = Docket::from_bytes(&std::fs::read(&docket_path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&docket_path);
+        let _ = std::fs::remove_file(data_file_path(&docket_path, &docket.data_uuid));
+    }
+
+    #[test]
+    fn sequential_merges_keep_var_ids_and_symbol_index_distinct() {
+        let mut parser_state = Arc::new(ParserState::new());
+
+        let mut first_working_set = ParserWorkingSet::new(Some(parser_state.clone()));
+        first_working_set.enter_scope();
+        let first_decl = Span {
+            file_id: 0,
+            start: 0,
+            end: 1,
+        };
+        let first_id = first_working_set.add_variable(b"x".to_vec(), first_decl, Type::Int);
+        ParserState::merge_working_set(&mut parser_state, first_working_set);
+
+        let mut second_working_set = ParserWorkingSet::new(Some(parser_state.clone()));
+        second_working_set.enter_scope();
+        let second_decl = Span {
+            file_id: 0,
+            start: 5,
+            end: 6,
+        };
+        let second_id = second_working_set.add_variable(b"y".to_vec(), second_decl, Type::Unknown);
+        ParserState::merge_working_set(&mut parser_state, second_working_set);
+
+        // The second round's id must not collide with the first's.
+        assert_ne!(first_id, second_id);
+
+        // Both variables' types and definitions must still be correct and
+        // distinct after both merges.
+        assert!(matches!(parser_state.vars.get(&first_id), Some(Type::Int)));
+        assert!(matches!(parser_state.vars.get(&second_id), Some(Type::Unknown)));
+
+        let first_definition = parser_state.definition_span(first_id).unwrap();
+        assert_eq!((first_definition.start, first_definition.end), (0, 1));
+        let second_definition = parser_state.definition_span(second_id).unwrap();
+        assert_eq!((second_definition.start, second_definition.end), (5, 6));
+
+        assert_eq!(parser_state.symbol_at(first_decl), Some(first_id));
+        assert_eq!(parser_state.symbol_at(second_decl), Some(second_id));
+    }
+
+    fn dummy_span() -> Span {
+        Span {
+            file_id: 0,
+            start: 0,
+            end: 1,
+        }
+    }
+
+    #[test]
+    fn exit_scope_pops_the_frame() {
+        let mut working_set = ParserWorkingSet::new(Some(Arc::new(ParserState::new())));
+        working_set.enter_scope();
+        working_set.add_variable(b"x".to_vec(), dummy_span(), Type::Int);
+        assert!(working_set.find_variable(b"x").is_some());
+
+        working_set.enter_scope();
+        assert!(working_set.find_variable(b"x").is_some());
+
+        working_set.exit_scope();
+        assert!(working_set.find_variable(b"x").is_some());
+
+        working_set.exit_scope();
+        assert!(working_set.find_variable(b"x").is_none());
+    }
+
+    #[test]
+    fn inner_scope_shadows_outer() {
+        let mut working_set = ParserWorkingSet::new(Some(Arc::new(ParserState::new())));
+        working_set.enter_scope();
+        let outer_id = working_set.add_variable(b"x".to_vec(), dummy_span(), Type::Unknown);
+
+        working_set.enter_scope();
+        let inner_id = working_set.add_variable(b"x".to_vec(), dummy_span(), Type::Int);
+
+        let (found_id, location, ty) = working_set.find_variable(b"x").unwrap();
+        assert_eq!(found_id, inner_id);
+        assert_ne!(found_id, outer_id);
+        assert!(matches!(location, VarLocation::CurrentScope));
+        assert!(matches!(ty, Type::Int));
+    }
+
+    #[test]
+    fn unset_masks_outer_scope_binding() {
+        let mut working_set = ParserWorkingSet::new(Some(Arc::new(ParserState::new())));
+        working_set.enter_scope();
+        working_set.add_variable(b"x".to_vec(), dummy_span(), Type::Int);
+
+        working_set.enter_scope();
+        working_set.unset_variable(b"x");
+
+        assert!(working_set.find_variable(b"x").is_none());
+
+        // The removal is scoped to the inner frame; popping it restores the
+        // outer binding instead of leaving it permanently erased.
+        working_set.exit_scope();
+        assert!(working_set.find_variable(b"x").is_some());
+    }
+
+    #[test]
+    fn shadow_then_unmask_across_nested_overlays() {
+        let mut working_set = ParserWorkingSet::new(Some(Arc::new(ParserState::new())));
+        working_set.enter_scope();
+        let outer_id = working_set.add_variable(b"x".to_vec(), dummy_span(), Type::Unknown);
+
+        working_set.enter_scope();
+        working_set.unset_variable(b"x");
+        assert!(working_set.find_variable(b"x").is_none());
+
+        working_set.enter_scope();
+        let reshadowed_id = working_set.add_variable(b"x".to_vec(), dummy_span(), Type::Int);
+
+        let (found_id, location, ty) = working_set.find_variable(b"x").unwrap();
+        assert_eq!(found_id, reshadowed_id);
+        assert_ne!(found_id, outer_id);
+        assert!(matches!(location, VarLocation::CurrentScope));
+        assert!(matches!(ty, Type::Int));
+
+        // Popping the re-adding frame falls back to the unset mask, not the
+        // original outer binding.
+        working_set.exit_scope();
+        assert!(working_set.find_variable(b"x").is_none());
+
+        // Popping the unset frame too restores the outer binding.
+        working_set.exit_scope();
+        let (found_id, location, _) = working_set.find_variable(b"x").unwrap();
+        assert_eq!(found_id, outer_id);
+        assert!(matches!(location, VarLocation::CurrentScope));
     }
 }